@@ -0,0 +1,202 @@
+//! A hand-rolled DEFLATE/zlib encoder specialised for one input: an arbitrarily long run of
+//! zero bytes. `flate2` (even at `Compression::best()`) still spends real work discovering that
+//! the input is trivially repetitive; here we already know that, so we can skip straight to the
+//! theoretically-optimal encoding instead of paying for a general-purpose compressor.
+//!
+//! The trick is a single dynamic-Huffman block that only ever defines three literal/length codes
+//! (literal `0`, end-of-block `256`, and the length code for a 258-byte match `285`), plus two
+//! 1-bit distance codes of which only `0` ("copy from one byte back") is used. Every byte after
+//! the first, bar a tail of fewer than 258 bytes sent as literals, is then emitted as part of a
+//! 258-byte back-reference costing 2 bits, which is DEFLATE's hard ceiling of 1032:1.
+use std::io::{self, Write};
+
+/// Adler-32 of `n` consecutive zero bytes, computed in O(1) instead of O(n).
+///
+/// Each zero byte leaves the running sum `a` unchanged (it's seeded at 1), so `a` stays `1`
+/// forever; `b` accumulates `a` once per byte, so it's just `n mod 65521`. This closed form only
+/// holds for zero bytes: the function takes a length, not data, and gives no valid checksum for
+/// any other byte pattern.
+fn adler32_of_zeros(n: usize) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let a: u32 = 1;
+    let mut b: u32 = 0;
+    // Zero bytes never change `a`, so this is equivalent to running the loop `n` times, but
+    // without actually doing so.
+    let steps = (n as u64) % (MOD_ADLER as u64);
+    b = ((b as u64 + a as u64 * steps) % MOD_ADLER as u64) as u32;
+    (b << 16) | a
+}
+
+/// Packs bits LSB-first into bytes and writes completed bytes straight to `w`, so the compressed
+/// stream never needs to be buffered in full.
+struct BitWriter<'w, W: Write> {
+    w: &'w mut W,
+    acc: u8,
+    nbits: u8,
+}
+
+impl<'w, W: Write> BitWriter<'w, W> {
+    fn new(w: &'w mut W) -> Self {
+        Self {
+            w,
+            acc: 0,
+            nbits: 0,
+        }
+    }
+
+    fn push_bit(&mut self, bit: u8) -> io::Result<()> {
+        self.acc |= (bit & 1) << self.nbits;
+        self.nbits += 1;
+        if self.nbits == 8 {
+            self.w.write_all(&[self.acc])?;
+            self.acc = 0;
+            self.nbits = 0;
+        }
+        Ok(())
+    }
+
+    /// Writes an ordinary DEFLATE field (block header bits, extra bits, code lengths): bits are
+    /// packed LSB-first, i.e. bit 0 of `value` goes into the stream first.
+    fn write_bits(&mut self, value: u32, n: u8) -> io::Result<()> {
+        for i in 0..n {
+            self.push_bit(((value >> i) & 1) as u8)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a Huffman code: per RFC 1951 §3.1.1, Huffman codes are packed starting with their
+    /// *most*-significant bit, unlike every other field in the format.
+    fn write_huffman(&mut self, code: u32, n: u8) -> io::Result<()> {
+        for i in (0..n).rev() {
+            self.push_bit(((code >> i) & 1) as u8)?;
+        }
+        Ok(())
+    }
+
+    /// Pads the final partial byte with zero bits and flushes it.
+    fn finish(mut self) -> io::Result<()> {
+        while self.nbits != 0 {
+            self.push_bit(0)?;
+        }
+        Ok(())
+    }
+}
+
+// The Huffman codes below are fixed: they don't depend on `width`/`height`/`n`, only on the shape
+// of the tree we chose (3 literal/length codes, 2 distance codes). See the module docs for why
+// these particular lengths/codes were picked.
+const LIT_ZERO: (u32, u8) = (0b10, 2); // literal 0
+const LIT_EOB: (u32, u8) = (0b11, 2); // end-of-block (256)
+const LIT_LEN258: (u32, u8) = (0b0, 1); // length code 285 (258-byte match, no extra bits)
+const DIST_ONE: (u32, u8) = (0b0, 1); // distance code 0 (distance 1, no extra bits)
+
+/// Writes the dynamic-Huffman block header describing the literal/length and distance trees
+/// above, via the code-length alphabet (RFC 1951 §3.2.7).
+///
+/// The code-length sequence this header encodes is always the same 288 entries: `[2, 0x255,
+/// 2, 0x28, 1, 1, 1]` (lengths 2,2 for literal 0 and EOB 256; length 1 for length-code 285;
+/// length 1 for each of the two distance codes; everything else unused). That only ever takes
+/// three code-length symbols to express - `1`, `2` and `18` (the "repeat zero 11-138 times"
+/// symbol) - so their own Huffman code is hand-picked here rather than built generically.
+fn write_block_header<W: Write>(bw: &mut BitWriter<W>) -> io::Result<()> {
+    bw.write_bits(1, 1)?; // BFINAL = 1, this is the only block
+    bw.write_bits(0b10, 2)?; // BTYPE = 10, dynamic Huffman codes
+
+    bw.write_bits(29, 5)?; // HLIT: 257 + 29 = 286 literal/length codes (covers symbol 285)
+    bw.write_bits(1, 5)?; // HDIST: 1 + 1 = 2 distance codes
+    bw.write_bits(15, 4)?; // HCLEN: 4 + 15 = 19 code-length codes (all of them, for simplicity)
+
+    // Code-length alphabet codes, hand-assigned to satisfy Kraft's equality over {1, 2, 18}:
+    // symbol 18 -> 1 bit (0), symbols 1 and 2 -> 2 bits (10, 11).
+    const CL_SYM1: (u32, u8) = (0b10, 2);
+    const CL_SYM2: (u32, u8) = (0b11, 2);
+    const CL_SYM18: (u32, u8) = (0b0, 1);
+
+    // Code-length code lengths, 3 bits each, in RFC 1951's fixed transmission order. Only
+    // symbols 1, 2 and 18 are used; everything else is absent (length 0).
+    const CLCL_ORDER: [u8; 19] = [
+        16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+    ];
+    for &sym in CLCL_ORDER.iter() {
+        let len = match sym {
+            1 | 2 => 2,
+            18 => 1,
+            _ => 0,
+        };
+        bw.write_bits(len, 3)?;
+    }
+
+    // The code-length sequence itself, run-length encoded:
+    //   symbol 0        -> length 2   (one entry, via CL_SYM2)
+    //   symbols 1..=255 -> length 0   (255 zeros, via two CL_SYM18 repeats: 138 + 117)
+    //   symbol 256      -> length 2   (one entry, via CL_SYM2)
+    //   symbols 257..=284 -> length 0 (28 zeros, via one CL_SYM18 repeat)
+    //   symbol 285      -> length 1   (one entry, via CL_SYM1)
+    //   distance 0, 1   -> length 1   (two entries, via CL_SYM1 twice)
+    bw.write_huffman(CL_SYM2.0, CL_SYM2.1)?;
+    bw.write_huffman(CL_SYM18.0, CL_SYM18.1)?;
+    bw.write_bits(138 - 11, 7)?; // repeat zero 138 times
+    bw.write_huffman(CL_SYM18.0, CL_SYM18.1)?;
+    bw.write_bits(117 - 11, 7)?; // repeat zero 117 times
+    bw.write_huffman(CL_SYM2.0, CL_SYM2.1)?;
+    bw.write_huffman(CL_SYM18.0, CL_SYM18.1)?;
+    bw.write_bits(28 - 11, 7)?; // repeat zero 28 times
+    bw.write_huffman(CL_SYM1.0, CL_SYM1.1)?;
+    bw.write_huffman(CL_SYM1.0, CL_SYM1.1)?;
+    bw.write_huffman(CL_SYM1.0, CL_SYM1.1)?;
+
+    Ok(())
+}
+
+/// How many matches to emit between `progress` calls - frequent enough for a responsive progress
+/// bar, infrequent enough not to spend more time reporting progress than making it.
+const PROGRESS_BATCH: usize = 4096;
+
+/// Writes `n` zero bytes' worth of compressed data, wrapped in a zlib stream, to `w`. `progress`
+/// is called periodically with the number of (virtual, decompressed) bytes emitted since the last
+/// call, so callers can drive a progress bar without us having to materialise the decompressed
+/// stream.
+pub fn write_zero_zlib<W: Write>(
+    w: &mut W,
+    n: usize,
+    mut progress: impl FnMut(u64),
+) -> io::Result<()> {
+    // zlib header: CMF=0x78 (deflate, 32K window), FLG=0x01 (no preset dictionary, checked to be
+    // a multiple of 31 together with CMF as required by RFC 1950).
+    w.write_all(&[0x78, 0x01])?;
+
+    let mut bw = BitWriter::new(w);
+    write_block_header(&mut bw)?;
+
+    // Seed the output with one literal byte, then copy 258 bytes at a time from one byte back
+    // for as long as a full copy still fits. Whatever's left over (0..257 bytes) can't be
+    // expressed as one more 258-byte copy without overshooting `n`, so it's emitted as individual
+    // literal-0 symbols instead - more expensive per byte, but it's at most 257 of them, and it's
+    // what keeps this producing exactly `n` decompressed bytes rather than rounding up to the
+    // next multiple of 258.
+    if n > 0 {
+        bw.write_huffman(LIT_ZERO.0, LIT_ZERO.1)?;
+        let copies = (n - 1) / 258;
+        let remainder = (n - 1) % 258;
+        for i in 0..copies {
+            bw.write_huffman(LIT_LEN258.0, LIT_LEN258.1)?;
+            bw.write_huffman(DIST_ONE.0, DIST_ONE.1)?;
+            if (i + 1) % PROGRESS_BATCH == 0 {
+                progress((PROGRESS_BATCH * 258) as u64);
+            }
+        }
+        // The seed literal, plus whatever copies the loop above hasn't reported yet.
+        progress((1 + (copies % PROGRESS_BATCH) * 258) as u64);
+        for _ in 0..remainder {
+            bw.write_huffman(LIT_ZERO.0, LIT_ZERO.1)?;
+        }
+        if remainder > 0 {
+            progress(remainder as u64);
+        }
+    }
+    bw.write_huffman(LIT_EOB.0, LIT_EOB.1)?;
+    bw.finish()?;
+
+    w.write_all(&adler32_of_zeros(n).to_be_bytes())?;
+    Ok(())
+}
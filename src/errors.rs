@@ -4,5 +4,6 @@ error_chain! {
     foreign_links {
         IO(std::io::Error);
         Docopt(docopt::Error);
+        Decompress(flate2::DecompressError);
     }
 }
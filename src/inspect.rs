@@ -0,0 +1,269 @@
+//! The other half of this crate: instead of generating a PNG that lies about how much memory
+//! it needs, walk an existing one and work out the truth, without ever holding the decoded
+//! image (or even the full compressed stream) in memory at once.
+use crate::errors::Result;
+use crate::{ADAM7_XSTART, ADAM7_XSTRIDE, ADAM7_YSTART, ADAM7_YSTRIDE};
+use crc::crc32::{self, Hasher32};
+use error_chain::bail;
+use flate2::{Decompress, FlushDecompress, Status};
+use std::io::Read;
+
+const SIGNATURE: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+
+/// PNG's own ceiling on a chunk's data length (the spec's "Chunk Layout" section: the length
+/// field "must not exceed 2^31-1 bytes"), checked before we act on an attacker-controlled length
+/// at all.
+const MAX_CHUNK_LEN: usize = (1 << 31) - 1;
+
+/// Chunk payloads are streamed through a buffer this size rather than allocated in one go, so a
+/// forged length on an `IDAT`/`fdAT` (or any other) chunk can't make us allocate on the strength
+/// of four attacker-controlled bytes - see `read_chunk_data`.
+const READ_CHUNK: usize = 64 * 1024;
+
+struct Ihdr {
+    width: u32,
+    height: u32,
+    bit_depth: u8,
+    color_type: u8,
+    interlaced: bool,
+}
+
+/// Number of samples per pixel for a raw PNG color type byte (IHDR §11.2.2), as opposed to the
+/// `png::ColorType` enum, since at this point we don't know the file is well-formed enough to
+/// hand to that crate.
+fn samples_per_pixel(color_type: u8) -> Result<u32> {
+    Ok(match color_type {
+        0 => 1, // grayscale
+        2 => 3, // rgb
+        3 => 1, // indexed
+        4 => 2, // grayscale+alpha
+        6 => 4, // rgba
+        _ => bail!("unknown color type byte: {}", color_type),
+    })
+}
+
+fn raw_row_length(width: u32, bit_depth: u8, color_type: u8) -> Result<usize> {
+    let bits_per_pixel = samples_per_pixel(color_type)? * bit_depth as u32;
+    let row_bytes = (width as usize * bits_per_pixel as usize).div_ceil(8);
+    Ok(1 + row_bytes) // +1 for the per-scanline filter byte
+}
+
+/// The number of raw (pre-compression) bytes a conforming decoder needs to hold a single frame
+/// of the image described by `ihdr`, summing the 7 Adam7 passes if it's interlaced. Width and
+/// height are both wire-controlled `u32`s, so every multiply/accumulation here saturates rather
+/// than wrapping `usize` - the whole point is to report an accurate (or at least not-smaller)
+/// figure for implausibly large declared dimensions, not to panic or quietly undercount them.
+fn required_bytes(ihdr: &Ihdr) -> Result<usize> {
+    if !ihdr.interlaced {
+        return Ok(
+            raw_row_length(ihdr.width, ihdr.bit_depth, ihdr.color_type)?.saturating_mul(ihdr.height as usize),
+        );
+    }
+
+    let mut total: usize = 0;
+    for i in 0..7 {
+        if ihdr.width <= ADAM7_XSTART[i] || ihdr.height <= ADAM7_YSTART[i] {
+            continue;
+        }
+        let pass_width = (ihdr.width - ADAM7_XSTART[i]).div_ceil(ADAM7_XSTRIDE[i]);
+        let pass_height = (ihdr.height - ADAM7_YSTART[i]).div_ceil(ADAM7_YSTRIDE[i]);
+        let pass_bytes = raw_row_length(pass_width, ihdr.bit_depth, ihdr.color_type)?
+            .saturating_mul(pass_height as usize);
+        total = total.saturating_add(pass_bytes);
+    }
+    Ok(total)
+}
+
+/// Reads exactly `len` bytes of chunk payload from `r` in pieces no larger than `READ_CHUNK`,
+/// folding each piece into `crc` and handing it to `sink` - so neither we nor `sink` ever need to
+/// hold the full declared length in memory at once, no matter how large `len` claims to be.
+fn read_chunk_data<R: Read>(
+    r: &mut R,
+    len: usize,
+    crc: &mut crc32::Digest,
+    mut sink: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buf = [0; READ_CHUNK];
+    let mut remaining = len;
+    while remaining > 0 {
+        let take = remaining.min(buf.len());
+        r.read_exact(&mut buf[..take])?;
+        crc.write(&buf[..take]);
+        sink(&buf[..take])?;
+        remaining -= take;
+    }
+    Ok(())
+}
+
+/// Reads a PNG from `r` chunk by chunk, verifying CRCs and streaming the IDAT/fdAT data through
+/// a zlib decoder with a fixed-size scratch buffer, and reports the allocation a naive decoder
+/// would make for it. Flags the file if either the declared or the actual decompressed size
+/// exceeds `max_bytes`.
+pub fn inspect<R: Read>(mut r: R, max_bytes: usize) -> Result<()> {
+    let mut sig = [0; 8];
+    r.read_exact(&mut sig)?;
+    if sig != SIGNATURE {
+        bail!("not a PNG file: bad signature");
+    }
+
+    let mut ihdr: Option<Ihdr> = None;
+    let mut num_frames: Option<u32> = None;
+    let mut compressed_bytes: usize = 0;
+    let mut decompressed_bytes: u64 = 0;
+    // Consecutive IDAT (or fdAT) chunks are one continuous zlib stream, but each fcTL chunk in
+    // an APNG starts a brand new one for its frame - so a fresh `Decompress` is needed whenever
+    // a non-data chunk breaks the run.
+    let mut decomp: Option<Decompress> = None;
+    let mut scratch = [0; 64 * 1024];
+
+    loop {
+        let mut lenbuf = [0; 4];
+        r.read_exact(&mut lenbuf)?;
+        let len = u32::from_be_bytes(lenbuf) as usize;
+        if len > MAX_CHUNK_LEN {
+            bail!(
+                "declared chunk length {} exceeds the PNG limit of {}",
+                len,
+                MAX_CHUNK_LEN
+            );
+        }
+
+        let mut typ = [0; 4];
+        r.read_exact(&mut typ)?;
+
+        let mut crc = crc32::Digest::new(crc32::IEEE);
+        crc.write(&typ);
+
+        match &typ {
+            b"IHDR" => {
+                if len != 13 {
+                    bail!("malformed IHDR chunk: expected 13 bytes, got {}", len);
+                }
+                let mut data = [0; 13];
+                let mut pos = 0;
+                read_chunk_data(&mut r, len, &mut crc, |piece| {
+                    data[pos..pos + piece.len()].copy_from_slice(piece);
+                    pos += piece.len();
+                    Ok(())
+                })?;
+                let width = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                let height = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+                let bit_depth = data[8];
+                let color_type = data[9];
+                let interlaced = data[12] != 0;
+                println!(
+                    "IHDR: {}x{}, {}bpp, color type {}{}",
+                    width,
+                    height,
+                    bit_depth,
+                    color_type,
+                    if interlaced { ", interlaced" } else { "" }
+                );
+                ihdr = Some(Ihdr {
+                    width,
+                    height,
+                    bit_depth,
+                    color_type,
+                    interlaced,
+                });
+            }
+            b"acTL" => {
+                if len != 8 {
+                    bail!("malformed acTL chunk: expected 8 bytes, got {}", len);
+                }
+                let mut data = [0; 8];
+                let mut pos = 0;
+                read_chunk_data(&mut r, len, &mut crc, |piece| {
+                    data[pos..pos + piece.len()].copy_from_slice(piece);
+                    pos += piece.len();
+                    Ok(())
+                })?;
+                let frames = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+                println!("acTL: {} frame(s)", frames);
+                num_frames = Some(frames);
+            }
+            b"IDAT" | b"fdAT" => {
+                // fdAT chunks are prefixed with a 4-byte sequence number before their zlib data;
+                // skip it as it streams by, rather than requiring the whole chunk up front.
+                let mut skip = if &typ == b"fdAT" { 4 } else { 0 };
+                let decomp = decomp.get_or_insert_with(|| Decompress::new(true));
+                read_chunk_data(&mut r, len, &mut crc, |piece| {
+                    let mut piece = piece;
+                    if skip > 0 {
+                        let n = skip.min(piece.len());
+                        piece = &piece[n..];
+                        skip -= n;
+                    }
+                    compressed_bytes += piece.len();
+
+                    let mut input = piece;
+                    loop {
+                        let before_in = decomp.total_in();
+                        let before_out = decomp.total_out();
+                        let status = decomp.decompress(input, &mut scratch, FlushDecompress::None)?;
+                        let consumed = (decomp.total_in() - before_in) as usize;
+                        decompressed_bytes += decomp.total_out() - before_out;
+                        input = &input[consumed..];
+                        if status == Status::StreamEnd || input.is_empty() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                })?;
+            }
+            b"IEND" => {
+                read_chunk_data(&mut r, len, &mut crc, |_| Ok(()))?;
+            }
+            _ => {
+                // Any other chunk (fcTL, PLTE, ...) ends the current data run.
+                decomp = None;
+                read_chunk_data(&mut r, len, &mut crc, |_| Ok(()))?;
+            }
+        }
+
+        let mut crcbuf = [0; 4];
+        r.read_exact(&mut crcbuf)?;
+        if crc.sum32() != u32::from_be_bytes(crcbuf) {
+            bail!("CRC mismatch in {} chunk", String::from_utf8_lossy(&typ));
+        }
+
+        if &typ == b"IEND" {
+            break;
+        }
+    }
+
+    let ihdr = ihdr.ok_or("missing IHDR chunk")?;
+    if compressed_bytes == 0 {
+        bail!("missing IDAT chunk");
+    }
+
+    // An acTL declares that every subsequent frame replays the same per-frame allocation, so a
+    // naive decoder holding on to each one pays for it `num_frames` times over. Both operands
+    // come straight off the wire, so saturate rather than overflow - a file that can genuinely
+    // make a decoder allocate `usize::MAX` bytes is flagged the same as one that could allocate
+    // more than that.
+    let frames = num_frames.unwrap_or(1).max(1) as usize;
+    let required = required_bytes(&ihdr)?.saturating_mul(frames);
+    let ratio = decompressed_bytes as f64 / compressed_bytes as f64;
+    if frames > 1 {
+        println!(
+            "Declared dimensions require {} bytes to decode across {} frame(s)",
+            required, frames
+        );
+    } else {
+        println!("Declared dimensions require {} bytes to decode", required);
+    }
+    println!(
+        "IDAT data decompresses to {} bytes from {} bytes ({:.1}:1 ratio)",
+        decompressed_bytes, compressed_bytes, ratio
+    );
+
+    if required > max_bytes || decompressed_bytes > max_bytes as u64 {
+        println!(
+            "FLAGGED: exceeds --max-bytes={} (a naive decoder would allocate more than that)",
+            max_bytes
+        );
+    }
+
+    Ok(())
+}
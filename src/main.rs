@@ -1,43 +1,17 @@
+pub mod deflate;
 pub mod errors;
+pub mod inspect;
 
 use crc::crc32::{self, Hasher32};
 use docopt::Docopt;
 use error_chain::{bail, quick_main};
 use errors::Result;
-use flate2::{bufread::ZlibEncoder, Compression};
 use pbr::ProgressBar;
 use serde::Deserialize;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 
-/// A BufRead implementation which just yields a set number of zeroes.
-pub struct ZeroReader {
-    pub count: usize,
-    pub at: usize,
-}
-
-impl ZeroReader {
-    pub fn new(count: usize) -> Self {
-        Self { count, at: 0 }
-    }
-}
-
-impl Read for ZeroReader {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let mut num = 0;
-        for c in buf.iter_mut() {
-            if self.at == self.count {
-                break;
-            }
-            *c = 0;
-            num += 1;
-            self.at += 1;
-        }
-        Ok(num)
-    }
-}
-
 pub struct ChunkWriter<W: io::Write + io::Seek> {
     w: W,
     len: Option<usize>,
@@ -103,79 +77,258 @@ pub fn write_chunk<W: io::Write + io::Seek>(w: W, typ: [u8; 4], data: &[u8]) ->
     cw.finish()
 }
 
-fn render<W: io::Write + io::Seek>(
+// Adam7 interlacing splits the image into 7 reduced-resolution passes, each covering a regular
+// grid of the full image. These are the starting offset/stride pairs from the PNG spec, §8.2.
+pub(crate) const ADAM7_XSTART: [u32; 7] = [0, 4, 0, 2, 0, 1, 0];
+pub(crate) const ADAM7_XSTRIDE: [u32; 7] = [8, 8, 4, 4, 2, 2, 1];
+pub(crate) const ADAM7_YSTART: [u32; 7] = [0, 0, 4, 0, 2, 0, 1];
+pub(crate) const ADAM7_YSTRIDE: [u32; 7] = [8, 8, 8, 4, 4, 2, 2];
+
+/// Number of raw (pre-compression) bytes one scanline of `width` pixels takes, including its
+/// filter byte - the same formula as `png::Info::raw_row_length()`, since we need it for
+/// arbitrary pass dimensions rather than the image's own.
+fn raw_row_length(width: u32, bit_depth: png::BitDepth, color_type: png::ColorType) -> usize {
+    let bits = width as usize * color_type.samples() * bit_depth as usize;
+    bits.div_ceil(8) + 1
+}
+
+/// How many raw (pre-compression) bytes an Adam7-interlaced image of the given size, bit depth
+/// and color type would take, summed across its 7 passes. Each pass is its own little image with
+/// its own per-scanline filter byte, so a naive decoder allocating per-pass buffers as well as
+/// the final output pays for all of this on top of the flat size.
+fn adam7_raw_byte_len(
+    width: u32,
+    height: u32,
+    bit_depth: png::BitDepth,
+    color_type: png::ColorType,
+) -> usize {
+    let mut total = 0;
+    for i in 0..7 {
+        if width <= ADAM7_XSTART[i] || height <= ADAM7_YSTART[i] {
+            continue;
+        }
+        let pass_width = (width - ADAM7_XSTART[i]).div_ceil(ADAM7_XSTRIDE[i]);
+        let pass_height = (height - ADAM7_YSTART[i]).div_ceil(ADAM7_YSTRIDE[i]);
+        total += raw_row_length(pass_width, bit_depth, color_type) * pass_height as usize;
+    }
+    total
+}
+
+fn parse_color_type(s: &str) -> Result<png::ColorType> {
+    Ok(match s {
+        "grayscale" => png::ColorType::Grayscale,
+        "rgb" => png::ColorType::RGB,
+        "rgba" => png::ColorType::RGBA,
+        "indexed" => png::ColorType::Indexed,
+        "grayscale-alpha" => png::ColorType::GrayscaleAlpha,
+        _ => bail!("unknown color type: {}", s),
+    })
+}
+
+fn parse_bit_depth(n: usize) -> Result<png::BitDepth> {
+    Ok(match n {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        8 => png::BitDepth::Eight,
+        16 => png::BitDepth::Sixteen,
+        _ => bail!("unknown bit depth: {}", n),
+    })
+}
+
+/// Not every color type/bit depth combination is legal - see the PNG spec, §11.2.2.
+fn validate_color_depth(color_type: png::ColorType, bit_depth: png::BitDepth) -> Result<()> {
+    use png::BitDepth::*;
+    use png::ColorType::*;
+    let legal: &[png::BitDepth] = match color_type {
+        Grayscale => &[One, Two, Four, Eight, Sixteen],
+        RGB => &[Eight, Sixteen],
+        Indexed => &[One, Two, Four, Eight],
+        GrayscaleAlpha => &[Eight, Sixteen],
+        RGBA => &[Eight, Sixteen],
+    };
+    if !legal.contains(&bit_depth) {
+        bail!(
+            "{:?} doesn't support a bit depth of {}",
+            color_type,
+            bit_depth as u32
+        );
+    }
+    Ok(())
+}
+
+/// Writes the 8-byte PNG signature, the IHDR chunk, and (for indexed color) a one-entry black
+/// PLTE chunk - the part of a PNG that's identical whether it's a single still image or an APNG.
+fn write_signature_and_ihdr<W: io::Write + io::Seek>(
     mut out: W,
     width: usize,
     height: usize,
     color_type: png::ColorType,
     bit_depth: png::BitDepth,
+    interlaced: bool,
 ) -> Result<W> {
-    // Figure out how many bytes of image data to generate.
-    // Calculations lifted from the png crate.
-    let info = png::Info {
-        width: width as u32,
-        height: height as u32,
-        bit_depth: bit_depth,
-        color_type: color_type,
-        interlaced: false,
-        palette: None,
-        trns: None,
-        pixel_dims: None,
-        frame_control: None,
-        animation_control: None,
-        compression: png::Compression::Best,
-        filter: png::FilterType::NoFilter,
-    };
-    //let in_len = info.raw_row_length() - 1;
-    //let data_size = in_len * info.height as usize;
-
-    println!(
-        "Generating PNG: {}x{}, {}bpp, {:?}",
-        width, height, bit_depth as u32, color_type
-    );
-
     print!("Header: ");
     out.write_all(&[137, 80, 78, 71, 13, 10, 26, 10])?;
     println!("done!");
 
-    // Write the IHDR chunk.
     print!("IHDR: ");
     let mut hdr = [0; 13];
     (&mut hdr[..]).write_all(&(width as u32).to_be_bytes())?;
     (&mut hdr[4..]).write_all(&(height as u32).to_be_bytes())?;
     hdr[8] = bit_depth as u8;
     hdr[9] = color_type as u8;
+    hdr[12] = interlaced as u8;
     out = write_chunk(out, png::chunk::IHDR, &hdr)?;
     println!("done!");
 
-    // PNG bitmap data is grouped in "scanlines", eg. data for one horizontal line, prefixed with
-    // a 1-byte filter mode flag. We're using no filters (0) and all-black (0) pixels, we just want
-    // to generate a whole pile of deflated zeroes, but without allocating it all upfront.
-    let ibytes = info.raw_row_length() * height;
-    let idata = ZeroReader::new(ibytes);
-    let mut zdata = ZlibEncoder::new(
-        io::BufReader::with_capacity(64 * 1024, idata),
-        Compression::new(4),
-    );
+    // Indexed color needs a palette; since every pixel is 0 (index 0, filter byte aside), a
+    // single black entry is all any decoder ever actually looks up.
+    if color_type == png::ColorType::Indexed {
+        print!("PLTE: ");
+        out = write_chunk(out, png::chunk::PLTE, &[0, 0, 0])?;
+        println!("done!");
+    }
+
+    Ok(out)
+}
 
-    // Write it to an IDAT chunk.
+/// How many raw (pre-compression) bytes one frame of `width`x`height` pixels takes to decode,
+/// accounting for Adam7 interlacing if requested - see `adam7_raw_byte_len` for why that's more
+/// than just `raw_row_length() * height`.
+fn frame_byte_len(
+    width: usize,
+    height: usize,
+    bit_depth: png::BitDepth,
+    color_type: png::ColorType,
+    interlaced: bool,
+) -> usize {
+    if interlaced {
+        adam7_raw_byte_len(width as u32, height as u32, bit_depth, color_type)
+    } else {
+        raw_row_length(width as u32, bit_depth, color_type) * height
+    }
+}
+
+/// Writes one frame's worth of all-zero pixel data to a freshly-begun `chunk_type` chunk (`IDAT`
+/// or `fdAT`), hand-encoded via `deflate::write_zero_zlib` instead of compressing real zeroes -
+/// see that module for why this gets so much closer to DEFLATE's 1032:1 ceiling. `seq_prefix`,
+/// when given, is written before the zlib data as `fdAT`'s sequence number.
+fn write_frame_data<W: io::Write + io::Seek>(
+    out: W,
+    chunk_type: [u8; 4],
+    seq_prefix: Option<u32>,
+    ibytes: usize,
+    label: &str,
+) -> Result<W> {
     let mut pb = ProgressBar::new(ibytes as u64);
     pb.set_units(pbr::Units::Bytes);
-    pb.message("IDAT: ");
-    let mut idat = ChunkWriter::begin(out, png::chunk::IDAT, None)?;
-    let mut buf = [0; 2 * 1024 * 1024];
-    loop {
-        let len = zdata.read(&mut buf[..])?;
-        if len == 0 {
-            break;
-        }
-        idat.write_all(&buf[..len])?;
-        pb.add(len as u64);
+    pb.message(label);
+    let mut chunk = ChunkWriter::begin(out, chunk_type, None)?;
+    if let Some(seq) = seq_prefix {
+        chunk.write_all(&seq.to_be_bytes())?;
     }
+    deflate::write_zero_zlib(&mut chunk, ibytes, |n| {
+        pb.add(n);
+    })?;
     pb.finish();
-    out = idat.finish()?;
+    chunk.finish()
+}
+
+fn render<W: io::Write + io::Seek>(
+    out: W,
+    width: usize,
+    height: usize,
+    color_type: png::ColorType,
+    bit_depth: png::BitDepth,
+    interlaced: bool,
+) -> Result<W> {
+    println!(
+        "Generating PNG: {}x{}, {}bpp, {:?}{}",
+        width,
+        height,
+        bit_depth as u32,
+        color_type,
+        if interlaced { ", interlaced" } else { "" }
+    );
+
+    let mut out = write_signature_and_ihdr(out, width, height, color_type, bit_depth, interlaced)?;
+
+    // PNG bitmap data is grouped in "scanlines", eg. data for one horizontal line, prefixed with
+    // a 1-byte filter mode flag. We're using no filters (0) and all-black (0) pixels.
+    let ibytes = frame_byte_len(width, height, bit_depth, color_type, interlaced);
+    out = write_frame_data(out, png::chunk::IDAT, None, ibytes, "IDAT: ")?;
+
+    print!("IEND: ");
+    out = write_chunk(out, png::chunk::IEND, &[])?;
+    println!("done!");
+
+    Ok(out)
+}
+
+/// Like `render()`, but emits an animated PNG with `frames` identical all-zero frames instead of
+/// a single image. Every frame is the same compressible zero stream, so the file barely grows
+/// while the decoded pixel volume a naive player holds onto multiplies by `frames`.
+fn render_animated<W: io::Write + io::Seek>(
+    out: W,
+    width: usize,
+    height: usize,
+    color_type: png::ColorType,
+    bit_depth: png::BitDepth,
+    interlaced: bool,
+    frames: usize,
+) -> Result<W> {
+    println!(
+        "Generating animated PNG: {}x{}, {}bpp, {:?}, {} frames",
+        width, height, bit_depth as u32, color_type, frames
+    );
+
+    let mut out = write_signature_and_ihdr(out, width, height, color_type, bit_depth, interlaced)?;
+
+    // acTL must come before the first IDAT: num_frames, num_plays (0 = loop forever).
+    print!("acTL: ");
+    let mut actl = [0; 8];
+    (&mut actl[..]).write_all(&(frames as u32).to_be_bytes())?;
+    (&mut actl[4..]).write_all(&0u32.to_be_bytes())?;
+    out = write_chunk(out, png::chunk::acTL, &actl)?;
+    println!("done!");
+
+    let ibytes = frame_byte_len(width, height, bit_depth, color_type, interlaced);
+
+    // Sequence numbers are shared between fcTL and fdAT chunks and count up across the whole
+    // file, starting at 0.
+    let mut seq: u32 = 0;
+    for frame in 0..frames {
+        print!("fcTL[{}]: ", frame);
+        let mut fctl = [0; 26];
+        (&mut fctl[..]).write_all(&seq.to_be_bytes())?;
+        seq += 1;
+        (&mut fctl[4..]).write_all(&(width as u32).to_be_bytes())?;
+        (&mut fctl[8..]).write_all(&(height as u32).to_be_bytes())?;
+        (&mut fctl[12..]).write_all(&0u32.to_be_bytes())?; // x_offset
+        (&mut fctl[16..]).write_all(&0u32.to_be_bytes())?; // y_offset
+        (&mut fctl[20..]).write_all(&1u16.to_be_bytes())?; // delay_num
+        (&mut fctl[22..]).write_all(&1u16.to_be_bytes())?; // delay_den: 1/1s per frame
+        fctl[24] = 0; // dispose_op: APNG_DISPOSE_OP_NONE
+        fctl[25] = 0; // blend_op: APNG_BLEND_OP_SOURCE
+        out = write_chunk(out, png::chunk::fcTL, &fctl)?;
+        println!("done!");
+
+        if frame == 0 {
+            // The first frame's data always goes in the regular IDAT chunk.
+            out = write_frame_data(out, png::chunk::IDAT, None, ibytes, "IDAT: ")?;
+        } else {
+            // Later frames go in fdAT chunks, each prefixed with its own sequence number.
+            out = write_frame_data(
+                out,
+                png::chunk::fdAT,
+                Some(seq),
+                ibytes,
+                &format!("fdAT[{}]: ", frame),
+            )?;
+            seq += 1;
+        }
+    }
 
-    // Write the IEND chunk.
     print!("IEND: ");
     out = write_chunk(out, png::chunk::IEND, &[])?;
     println!("done!");
@@ -184,31 +337,68 @@ fn render<W: io::Write + io::Seek>(
 }
 
 const USAGE: &str = "
-pngbomb - generate a very big PNG
+pngbomb - generate (and detect) a very big PNG
 
-Usage: pngbomb [options] <outfile>
+Usage:
+  pngbomb [options] <outfile>
+  pngbomb inspect [options] <file>
+  pngbomb --help
 
 Options:
-  -w PX --width=PX   Output width [default: 10000]
-  -h PX --height=PX  Output height [default: 10000]
+  -w PX --width=PX     Output width [default: 10000]
+  -h PX --height=PX    Output height [default: 10000]
+  --interlace          Use Adam7 interlacing, multiplying decoder allocation cost
+  --color=TYPE         Color type: grayscale, rgb, rgba, indexed, grayscale-alpha [default: grayscale]
+  --depth=BITS         Bit depth: 1, 2, 4, 8 or 16 [default: 1]
+  --frames=N           Emit an animated PNG with N identical frames instead of a still image [default: 0]
+  --max-bytes=N        inspect: flag files whose declared/decoded size exceeds this many bytes [default: 4294967296]
 ";
 
 #[derive(Deserialize)]
 struct Args {
+    cmd_inspect: bool,
     arg_outfile: String,
+    arg_file: String,
     flag_width: usize,
     flag_height: usize,
+    flag_interlace: bool,
+    flag_color: String,
+    flag_depth: usize,
+    flag_frames: usize,
+    flag_max_bytes: usize,
 }
 
 fn run() -> Result<()> {
     let args: Args = Docopt::new(USAGE)?.deserialize()?;
-    render(
-        &mut File::create(args.arg_outfile)?,
-        args.flag_width,
-        args.flag_height,
-        png::ColorType::Grayscale,
-        png::BitDepth::One,
-    )?;
+
+    if args.cmd_inspect {
+        return inspect::inspect(File::open(args.arg_file)?, args.flag_max_bytes);
+    }
+
+    let color_type = parse_color_type(&args.flag_color)?;
+    let bit_depth = parse_bit_depth(args.flag_depth)?;
+    validate_color_depth(color_type, bit_depth)?;
+    let mut out = File::create(args.arg_outfile)?;
+    if args.flag_frames > 0 {
+        render_animated(
+            &mut out,
+            args.flag_width,
+            args.flag_height,
+            color_type,
+            bit_depth,
+            args.flag_interlace,
+            args.flag_frames,
+        )?;
+    } else {
+        render(
+            &mut out,
+            args.flag_width,
+            args.flag_height,
+            color_type,
+            bit_depth,
+            args.flag_interlace,
+        )?;
+    }
     Ok(())
 }
 quick_main!(run);